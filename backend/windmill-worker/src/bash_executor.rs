@@ -8,6 +8,7 @@ use std::{
 use anyhow::Result;
 use async_recursion::async_recursion;
 use regex::Regex;
+use serde::{Deserialize, Serialize};
 use serde_json::{json, value::RawValue};
 use sqlx::types::Json;
 use tokio::process::Command;
@@ -15,12 +16,16 @@ use windmill_common::{error::Error, jobs::QueuedJob, worker::to_raw_value};
 use windmill_queue::{append_logs, CanceledBy};
 
 const BIN_BASH: &str = "/bin/bash";
+// Opt-in marker that switches a bash/powershell job to structured-output mode, where stdout,
+// stderr and the child exit code are captured as distinct channels and returned as a JSON object.
+const STRUCTURED_OUTPUT_PRAGMA: &str = "# windmill:structured_output";
 const NSJAIL_CONFIG_RUN_BASH_CONTENT: &str = include_str!("../nsjail/run.bash.config.proto");
 const NSJAIL_CONFIG_RUN_POWERSHELL_CONTENT: &str =
     include_str!("../nsjail/run.powershell.config.proto");
 
 lazy_static::lazy_static! {
-    static ref RE_POWERSHELL_IMPORTS: Regex = Regex::new(r#"^(?i)Import-Module(?-i)\s+(?:-Force\s+)?(?:-Name\s+)?(?:(?:"([^-\s"]+)")|(?:'([^-\s']+)')|([^-\s'"]+))"#).unwrap();
+    static ref RE_POWERSHELL_IMPORTS: Regex = Regex::new(r#"^(?i)Import-Module(?-i)\s+(?:-Force\s+)?(?:-Name\s+)?(?:(?:"([^-\s"]+)")|(?:'([^-\s']+)')|([^-\s'"]+))(?:\s+-RequiredVersion\s+(\S+))?"#).unwrap();
+    static ref RE_BASH_IMPORTS: Regex = Regex::new(r#"^\s*(?:source|\.)\s+(?:(?:"([^"]+)")|(?:'([^']+)')|([^\s'";]+))"#).unwrap();
 }
 
 use crate::{
@@ -37,6 +42,53 @@ lazy_static::lazy_static! {
     pub static ref ANSI_ESCAPE_RE: Regex = Regex::new(r"\x1b\[[0-9;]*m").unwrap();
 }
 
+/// Coarse lifecycle phase of a bash/powershell job, surfaced as a machine-readable signal so the
+/// UI/API can tell whether a job is resolving dependencies, installing modules, executing or
+/// collecting its result without parsing free-form logs.
+#[derive(Debug, Clone, Copy)]
+pub enum JobPhase {
+    ResolvingDeps,
+    InstallingModules,
+    Running,
+    CollectingResult,
+    Completed,
+    Failed,
+}
+
+impl JobPhase {
+    fn as_str(&self) -> &'static str {
+        match self {
+            JobPhase::ResolvingDeps => "resolving_deps",
+            JobPhase::InstallingModules => "installing_modules",
+            JobPhase::Running => "running",
+            JobPhase::CollectingResult => "collecting_result",
+            JobPhase::Completed => "completed",
+            JobPhase::Failed => "failed",
+        }
+    }
+}
+
+/// Persist the current phase (and the time of the transition) on the job row so it can be polled.
+/// Best-effort: a failure to record the phase must never take down the job itself.
+async fn report_job_phase(
+    db: &sqlx::Pool<sqlx::Postgres>,
+    job_id: &uuid::Uuid,
+    workspace_id: &str,
+    phase: JobPhase,
+) {
+    if let Err(e) = sqlx::query!(
+        "UPDATE queue SET current_phase = $1, phase_updated_at = now() WHERE id = $2 AND workspace_id = $3",
+        phase.as_str(),
+        job_id,
+        workspace_id,
+    )
+    .execute(db)
+    .await
+    {
+        tracing::error!("failed to report phase {} for job {job_id}: {e:#}", phase.as_str());
+    }
+}
+
 #[tracing::instrument(level = "trace", skip_all)]
 pub async fn handle_bash_job(
     mem_peak: &mut i32,
@@ -51,17 +103,33 @@ pub async fn handle_bash_job(
     worker_name: &str,
     envs: HashMap<String, String>,
 ) -> Result<Box<RawValue>, Error> {
-    let logs1 = "\n\n--- BASH CODE EXECUTION ---\n".to_string();
-    append_logs(&job.id, &job.workspace_id, logs1, db).await;
+    let mut logs1 = "\n\n--- BASH CODE EXECUTION ---\n".to_string();
 
-    write_file(job_dir, "main.sh", &format!("set -e\n{content}")).await?;
-    write_file(
+    report_job_phase(db, &job.id, &job.workspace_id, JobPhase::ResolvingDeps).await;
+    let mut visited_nodes: HashSet<String> = HashSet::new();
+    visited_nodes.insert("main".to_string());
+    let code_content = handle_bash_deps(
+        &mut visited_nodes,
+        job.script_path(),
+        &mut logs1,
         job_dir,
-        "wrapper.sh",
-        "set -o pipefail\nset -e\nmkfifo bp\ncat bp | tail -1 > ./result2.out &\n /bin/bash ./main.sh \"$@\" 2>&1 | tee bp\nwait $!",
+        db,
+        content,
     )
     .await?;
 
+    append_logs(&job.id, &job.workspace_id, logs1, db).await;
+
+    let structured_output = is_structured_output(content);
+
+    write_file(job_dir, "main.sh", &format!("set -e\n{code_content}")).await?;
+    let wrapper_content = if structured_output {
+        structured_wrapper("/bin/bash ./main.sh \"$@\"")
+    } else {
+        "set -o pipefail\nset -e\nmkfifo bp\ncat bp | tail -1 > ./result2.out &\n /bin/bash ./main.sh \"$@\" 2>&1 | tee bp\nwait $!".to_string()
+    };
+    write_file(job_dir, "wrapper.sh", &wrapper_content).await?;
+
     let token = client.get_token().await;
     let mut reserved_variables = get_reserved_variables(job, &token, db).await?;
     reserved_variables.insert("RUST_LOG".to_string(), "info".to_string());
@@ -83,9 +151,15 @@ pub async fn handle_bash_job(
         })
         .collect::<Vec<String>>();
     let args = args_owned.iter().map(|s| &s[..]).collect::<Vec<&str>>();
-    let _ = write_file(job_dir, "result.json", "").await?;
-    let _ = write_file(job_dir, "result.out", "").await?;
-    let _ = write_file(job_dir, "result2.out", "").await?;
+    if structured_output {
+        let _ = write_file(job_dir, "result_stdout.out", "").await?;
+        let _ = write_file(job_dir, "result_stderr.out", "").await?;
+        let _ = write_file(job_dir, "result_exit_code.out", "").await?;
+    } else {
+        let _ = write_file(job_dir, "result.json", "").await?;
+        let _ = write_file(job_dir, "result.out", "").await?;
+        let _ = write_file(job_dir, "result2.out", "").await?;
+    }
 
     let child = if !*DISABLE_NSJAIL {
         let _ = write_file(
@@ -133,7 +207,8 @@ pub async fn handle_bash_job(
             .stderr(Stdio::piped());
         start_child_process(bash_cmd, BIN_BASH).await?
     };
-    handle_child(
+    report_job_phase(db, &job.id, &job.workspace_id, JobPhase::Running).await;
+    let run_result = handle_child(
         &job.id,
         db,
         mem_peak,
@@ -146,35 +221,86 @@ pub async fn handle_bash_job(
         job.timeout,
         true,
     )
-    .await?;
+    .await;
+    if run_result.is_err() {
+        report_job_phase(db, &job.id, &job.workspace_id, JobPhase::Failed).await;
+    }
+    run_result?;
 
-    let result_json_path = format!("{job_dir}/result.json");
-    if let Ok(metadata) = tokio::fs::metadata(&result_json_path).await {
-        if metadata.len() > 0 {
-            return Ok(read_file(&result_json_path).await?);
-        }
+    report_job_phase(db, &job.id, &job.workspace_id, JobPhase::CollectingResult).await;
+
+    if structured_output {
+        // structured mode deliberately captures a non-zero exit in the result's `exit_code` field
+        // and still succeeds, so the caller can inspect it; the job outcome stays Ok and the phase
+        // stays Completed to match.
+        let (result, _exit_code) = collect_structured_output(job_dir).await?;
+        report_job_phase(db, &job.id, &job.workspace_id, JobPhase::Completed).await;
+        return Ok(result);
     }
 
-    let result_out_path = format!("{job_dir}/result.out");
-    if let Ok(metadata) = tokio::fs::metadata(&result_out_path).await {
-        if metadata.len() > 0 {
-            let result = read_file_content(&result_out_path).await?;
-            return Ok(to_raw_value(&json!(result)));
+    let result = {
+        let result_json_path = format!("{job_dir}/result.json");
+        if tokio::fs::metadata(&result_json_path)
+            .await
+            .map(|m| m.len() > 0)
+            .unwrap_or(false)
+        {
+            read_file(&result_json_path).await?
+        } else {
+            let result_out_path = format!("{job_dir}/result.out");
+            let result_out_path2 = format!("{job_dir}/result2.out");
+            if tokio::fs::metadata(&result_out_path)
+                .await
+                .map(|m| m.len() > 0)
+                .unwrap_or(false)
+            {
+                to_raw_value(&json!(read_file_content(&result_out_path).await?))
+            } else if tokio::fs::metadata(&result_out_path2).await.is_ok() {
+                let out = read_file_content(&result_out_path2).await?.trim().to_string();
+                to_raw_value(&json!(out))
+            } else {
+                to_raw_value(&json!(
+                    "No result.out, result2.out or result.json found"
+                ))
+            }
         }
-    }
+    };
 
-    let result_out_path2 = format!("{job_dir}/result2.out");
-    if tokio::fs::metadata(&result_out_path2).await.is_ok() {
-        let result = read_file_content(&result_out_path2)
-            .await?
-            .trim()
-            .to_string();
-        return Ok(to_raw_value(&json!(result)));
-    }
+    report_job_phase(db, &job.id, &job.workspace_id, JobPhase::Completed).await;
+    Ok(result)
+}
+
+fn is_structured_output(content: &str) -> bool {
+    content
+        .lines()
+        .any(|line| line.trim() == STRUCTURED_OUTPUT_PRAGMA)
+}
+
+// Wrapper for structured-output mode: capture stdout/stderr to distinct files while still teeing
+// both to the console so `append_logs` keeps streaming live output, and record the child's exit
+// status. The wrapper itself exits 0 so a non-zero script still yields the `{stdout, stderr,
+// exit_code}` result instead of being swallowed as a failed wrapper.
+fn structured_wrapper(run_command: &str) -> String {
+    format!(
+        "set -o pipefail\nmkfifo out_fifo err_fifo\ntee ./result_stdout.out < out_fifo &\nout_pid=$!\ntee ./result_stderr.out < err_fifo >&2 &\nerr_pid=$!\n{run_command} > out_fifo 2> err_fifo\necho -n $? > ./result_exit_code.out\nwait $out_pid\nwait $err_pid"
+    )
+}
 
-    Ok(to_raw_value(&json!(
-        "No result.out, result2.out or result.json found"
-    )))
+async fn collect_structured_output(job_dir: &str) -> Result<(Box<RawValue>, Option<i64>), Error> {
+    let stdout = read_file_content(&format!("{job_dir}/result_stdout.out"))
+        .await
+        .unwrap_or_default();
+    let stderr = read_file_content(&format!("{job_dir}/result_stderr.out"))
+        .await
+        .unwrap_or_default();
+    let exit_code = read_file_content(&format!("{job_dir}/result_exit_code.out"))
+        .await
+        .ok()
+        .and_then(|x| x.trim().parse::<i64>().ok());
+    Ok((
+        to_raw_value(&json!({ "stdout": stdout, "stderr": stderr, "exit_code": exit_code })),
+        exit_code,
+    ))
 }
 
 fn raw_to_string(x: &str) -> String {
@@ -205,10 +331,341 @@ fn parse_path(path: &Path) -> PathBuf {
     result
 }
 
+#[async_recursion]
+pub async fn handle_bash_deps(
+    visited_nodes: &mut HashSet<String>,
+    source_file_name: &str,
+    logs: &mut String,
+    job_dir: &str,
+    db: &sqlx::Pool<sqlx::Postgres>,
+    content: &str,
+) -> Result<String, Error> {
+    let mut code_content = content.to_string();
+    for line in content.lines() {
+        for cap in RE_BASH_IMPORTS.captures_iter(line) {
+            let raw_ref = cap
+                .get(1)
+                .unwrap_or_else(|| cap.get(2).unwrap_or_else(|| cap.get(3).unwrap()))
+                .as_str();
+            let mut module = raw_ref.to_string().replace(".sh", "");
+            if module.starts_with("f/") || module.starts_with("u/") || module.starts_with('.') {
+                if !module.starts_with("f/") && !module.starts_with("u/") && module.starts_with('.')
+                {
+                    let script_folder = Path::new(source_file_name)
+                        .parent()
+                        .unwrap()
+                        .join(module.clone());
+                    module = parse_path(&script_folder).to_str().unwrap().to_string();
+                }
+                // the resolved store path of the imported script is the base against which its own
+                // relative `source ./x.sh` imports must resolve during recursion
+                let imported_path = module.clone();
+                if module == *source_file_name {
+                    module = "main".to_string();
+                }
+                let file_name = format!("{}.sh", &module.replace('/', "."));
+                let file_name_dot_reference = format!("./{}", file_name);
+                let whole_match = cap.get(0).unwrap().as_str();
+                let import_string = whole_match.replace(raw_ref, &file_name_dot_reference);
+                code_content = code_content.replace(whole_match, &import_string);
+                if visited_nodes.contains(&module) {
+                    continue;
+                }
+                visited_nodes.insert(module.clone());
+                let content = sqlx::query_scalar!(
+                    "SELECT content FROM script where path = $1 ORDER BY created_at DESC",
+                    &module
+                )
+                .fetch_optional(db)
+                .await?;
+                if let Some(content) = content {
+                    if !Path::new(format!("{}/{}", job_dir, file_name).as_str()).exists() {
+                        write_file(
+                            job_dir,
+                            &file_name,
+                            &handle_bash_deps(
+                                visited_nodes,
+                                &imported_path,
+                                logs,
+                                job_dir,
+                                db,
+                                &content,
+                            )
+                            .await?,
+                        )
+                        .await?;
+                    }
+                } else {
+                    logs.push_str(&format!("\n{} not found in the script store", raw_ref));
+                }
+            }
+        }
+    }
+    return Ok(code_content);
+}
+
+// On-disk manifest tracking which PowerShell modules (and versions) are fully installed in
+// POWERSHELL_CACHE_DIR. It lets cache lookups pin a required version and, for modules it has
+// recorded, distinguish a completed install from a partially-written cache entry, which a bare
+// directory scan cannot do.
+const POWERSHELL_CACHE_INDEX_FILE: &str = ".windmill_cache_index.json";
+
+#[derive(Debug, Default, Serialize, Deserialize)]
+struct PowershellCacheIndex {
+    // lowercased module name -> installed version -> completion marker
+    modules: HashMap<String, HashMap<String, bool>>,
+}
+
+const POWERSHELL_CACHE_INDEX_LOCK: &str = ".windmill_cache_index.lock";
+// Give up waiting on the lock after this many attempts (~5s at 100ms each) and assume it is stale;
+// a crashed worker could otherwise leave the lock file behind forever.
+const POWERSHELL_CACHE_LOCK_MAX_ATTEMPTS: u32 = 50;
+
+// RAII guard for the cross-worker advisory lock on the manifest; removes the lock file on drop.
+struct CacheIndexLock;
+
+impl Drop for CacheIndexLock {
+    fn drop(&mut self) {
+        let _ = fs::remove_file(PowershellCacheIndex::lock_path());
+    }
+}
+
+impl PowershellCacheIndex {
+    fn path() -> String {
+        format!("{}/{}", POWERSHELL_CACHE_DIR, POWERSHELL_CACHE_INDEX_FILE)
+    }
+
+    fn lock_path() -> String {
+        format!("{}/{}", POWERSHELL_CACHE_DIR, POWERSHELL_CACHE_INDEX_LOCK)
+    }
+
+    fn load() -> PowershellCacheIndex {
+        fs::read_to_string(Self::path())
+            .ok()
+            .and_then(|c| serde_json::from_str(&c).ok())
+            .unwrap_or_default()
+    }
+
+    // Atomic write: serialize to a per-process temp file then rename() over the manifest, so a
+    // concurrent reader never observes a half-written file (the very partial-write this index
+    // exists to detect must not itself be partially written).
+    fn save_atomic(&self) -> Result<(), Error> {
+        let tmp = format!("{}.{}.tmp", Self::path(), std::process::id());
+        fs::write(&tmp, serde_json::to_string(self)?)?;
+        fs::rename(&tmp, Self::path())?;
+        Ok(())
+    }
+
+    // Acquire the cross-worker advisory lock by exclusively creating the lock file, spinning while
+    // another worker holds it and breaking a presumed-stale lock once the budget is exhausted.
+    async fn acquire_lock() -> Result<CacheIndexLock, Error> {
+        let lock_path = Self::lock_path();
+        let mut attempt: u32 = 0;
+        loop {
+            match std::fs::OpenOptions::new()
+                .write(true)
+                .create_new(true)
+                .open(&lock_path)
+            {
+                Ok(_) => return Ok(CacheIndexLock),
+                Err(e) if e.kind() == std::io::ErrorKind::AlreadyExists => {
+                    if attempt >= POWERSHELL_CACHE_LOCK_MAX_ATTEMPTS {
+                        // assume the holder crashed; steal the lock and retry
+                        let _ = fs::remove_file(&lock_path);
+                        attempt = 0;
+                        continue;
+                    }
+                    attempt += 1;
+                    tokio::time::sleep(tokio::time::Duration::from_millis(100)).await;
+                }
+                Err(e) => return Err(e.into()),
+            }
+        }
+    }
+
+    // Record a completed install durably and safely under contention: take the lock, reload the
+    // on-disk manifest (so we merge rather than clobber other workers' entries), add our entry, and
+    // write it back atomically.
+    async fn record_completed(module: &str, version: &str) -> Result<(), Error> {
+        let _lock = Self::acquire_lock().await?;
+        let mut index = Self::load();
+        index.mark_completed(module, version);
+        index.save_atomic()
+    }
+
+    // A module counts as cached only if the manifest records a completed install; when a version is
+    // required the match must be on that exact version, otherwise any completed version suffices.
+    fn is_installed(&self, module: &str, version: Option<&str>) -> bool {
+        match (self.modules.get(module), version) {
+            (Some(versions), Some(v)) => versions.get(v).copied().unwrap_or(false),
+            (Some(versions), None) => versions.values().any(|completed| *completed),
+            (None, _) => false,
+        }
+    }
+
+    fn mark_completed(&mut self, module: &str, version: &str) {
+        self.modules
+            .entry(module.to_string())
+            .or_default()
+            .insert(version.to_string(), true);
+    }
+
+    // Seed missing entries from the on-disk cache so a cache populated before the manifest existed
+    // (e.g. right after an upgrade) isn't treated as a miss and needlessly re-downloaded. Existing
+    // manifest entries are authoritative and left untouched; the directory scan only fills gaps.
+    // A seeded directory counts as a completed install, so a partially-written directory of a
+    // module or version the manifest has no entry for is not told apart from a complete one.
+    fn seed_from_cache_dir(&mut self) {
+        let entries = match fs::read_dir(POWERSHELL_CACHE_DIR) {
+            Ok(entries) => entries,
+            Err(_) => return,
+        };
+        for entry in entries.filter_map(|e| e.ok()) {
+            let path = entry.path();
+            if !path.is_dir() {
+                continue;
+            }
+            let module = entry.file_name().to_string_lossy().to_lowercase();
+            let versions = self.modules.entry(module).or_default();
+            // record every installed version subdir; fall back to "latest" if the layout is flat
+            let version_dirs = fs::read_dir(&path)
+                .into_iter()
+                .flatten()
+                .filter_map(|e| e.ok())
+                .filter(|e| e.path().is_dir())
+                .map(|e| e.file_name().to_string_lossy().to_string())
+                .collect::<Vec<_>>();
+            if version_dirs.is_empty() {
+                versions.entry("latest".to_string()).or_insert(true);
+            } else {
+                for version in version_dirs {
+                    versions.entry(version).or_insert(true);
+                }
+            }
+        }
+    }
+}
+
+// Split a version string into its numeric components so versions order numerically rather than
+// lexicographically (otherwise "10.0.0" would sort before "9.0.0"). Non-numeric suffixes (e.g.
+// pre-release tags) are ignored for ordering purposes.
+fn version_key(version: &str) -> Vec<u64> {
+    version
+        .split('.')
+        .map(|part| {
+            part.chars()
+                .take_while(|c| c.is_ascii_digit())
+                .collect::<String>()
+                .parse::<u64>()
+                .unwrap_or(0)
+        })
+        .collect()
+}
+
+// Save-Module installs into POWERSHELL_CACHE_DIR/<Module>/<version>/; read back the version it wrote
+// when the job didn't pin one so the manifest records a concrete version.
+fn discover_installed_version(module: &str) -> Option<String> {
+    let dir = format!("{}/{}", POWERSHELL_CACHE_DIR, module);
+    fs::read_dir(dir)
+        .ok()?
+        .filter_map(|e| e.ok())
+        .filter(|e| e.path().is_dir())
+        .map(|e| e.file_name().to_string_lossy().to_string())
+        .max_by(|a, b| version_key(a).cmp(&version_key(b)))
+}
+
+// Save-Module can fail on a transient PSGallery/network error; retry each module install a bounded
+// number of times with exponential backoff before giving up with the real error. `handle_child`
+// collapses a pwsh failure into a generic non-zero-exit error without the underlying stderr, so we
+// cannot reliably classify transient vs. deterministic failures and instead retry every
+// non-cancellation failure up to the budget — a deterministic error just fails once per attempt.
+const POWERSHELL_INSTALL_MAX_ATTEMPTS: u32 = 5;
+const POWERSHELL_INSTALL_BACKOFF_BASE_SECS: u64 = 3;
+
+async fn save_powershell_module(
+    module: &str,
+    version: Option<&str>,
+    cache_index: &mut PowershellCacheIndex,
+    mem_peak: &mut i32,
+    canceled_by: &mut Option<CanceledBy>,
+    job: &QueuedJob,
+    db: &sqlx::Pool<sqlx::Postgres>,
+    worker_name: &str,
+) -> Result<(), Error> {
+    // instead of using Install-Module, we use Save-Module so that we can specify the installation path
+    let install_string = match version {
+        Some(v) => format!(
+            "Save-Module -Path {} -Force {} -RequiredVersion {};",
+            POWERSHELL_CACHE_DIR, module, v
+        ),
+        None => format!("Save-Module -Path {} -Force {};", POWERSHELL_CACHE_DIR, module),
+    };
+    let mut attempt: u32 = 1;
+    loop {
+        append_logs(
+            &job.id,
+            &job.workspace_id,
+            format!("\nInstalling {module} (attempt {attempt}/{POWERSHELL_INSTALL_MAX_ATTEMPTS})..."),
+            db,
+        )
+        .await;
+        let child = Command::new("pwsh")
+            .args(["-Command", &install_string])
+            .stdout(Stdio::piped())
+            .stderr(Stdio::piped())
+            .spawn()?;
+        let result = handle_child(
+            &job.id,
+            db,
+            mem_peak,
+            canceled_by,
+            child,
+            false,
+            worker_name,
+            &job.workspace_id,
+            "powershell install",
+            job.timeout,
+            false,
+        )
+        .await;
+        match result {
+            Ok(()) => {
+                // key on the resolved version so a future versioned request can get a cache hit; fall
+                // back to the version dir Save-Module wrote, then to "latest" if it can't be read
+                let installed_version = version
+                    .map(|v| v.to_string())
+                    .or_else(|| discover_installed_version(module))
+                    .unwrap_or_else(|| "latest".to_string());
+                cache_index.mark_completed(&module.to_lowercase(), &installed_version);
+                PowershellCacheIndex::record_completed(&module.to_lowercase(), &installed_version)
+                    .await?;
+                return Ok(());
+            }
+            // a cancellation or an exhausted budget is terminal: surface it immediately rather than
+            // burning the remaining retry budget
+            Err(e) if canceled_by.is_some() || attempt >= POWERSHELL_INSTALL_MAX_ATTEMPTS => {
+                return Err(e)
+            }
+            Err(e) => {
+                let backoff = POWERSHELL_INSTALL_BACKOFF_BASE_SECS * 2u64.pow(attempt - 1);
+                append_logs(
+                    &job.id,
+                    &job.workspace_id,
+                    format!("\nInstall of {module} failed: {e}. Retrying in {backoff}s..."),
+                    db,
+                )
+                .await;
+                tokio::time::sleep(tokio::time::Duration::from_secs(backoff)).await;
+                attempt += 1;
+            }
+        }
+    }
+}
+
 #[async_recursion]
 pub async fn handle_powershell_deps(
-    install_string: &mut String,
-    installed_modules: &[String],
+    modules_to_install: &mut Vec<(String, Option<String>)>,
+    cache_index: &PowershellCacheIndex,
     visited_nodes: &mut HashSet<String>,
     source_file_name: &str,
     logs: &mut String,
@@ -223,8 +680,9 @@ pub async fn handle_powershell_deps(
                 .get(1)
                 .unwrap_or_else(|| cap.get(2).unwrap_or_else(|| cap.get(3).unwrap()))
                 .as_str();
+            let required_version = cap.get(4).map(|m| m.as_str().to_string());
             let mut module = raw_module.to_string().replace(".ps1", "");
-            if !installed_modules.contains(&module.to_lowercase()) {
+            if !cache_index.is_installed(&module.to_lowercase(), required_version.as_deref()) {
                 if module.starts_with("f/") || module.starts_with("u/") || module.starts_with('.') {
                     if !module.starts_with("f/")
                         && !module.starts_with("u/")
@@ -260,8 +718,8 @@ pub async fn handle_powershell_deps(
                                 job_dir,
                                 &file_name,
                                 &handle_powershell_deps(
-                                    install_string,
-                                    installed_modules,
+                                    modules_to_install,
+                                    cache_index,
                                     visited_nodes,
                                     source_file_name,
                                     logs,
@@ -277,10 +735,10 @@ pub async fn handle_powershell_deps(
                 } else {
                     // instead of using Install-Module, we use Save-Module so that we can specify the installation path
                     logs.push_str(&format!("\n{} not found in cache", raw_module));
-                    install_string.push_str(&format!(
-                        "Save-Module -Path {} -Force {};",
-                        POWERSHELL_CACHE_DIR, raw_module
-                    ));
+                    let entry = (raw_module.to_string(), required_version.clone());
+                    if !modules_to_install.contains(&entry) {
+                        modules_to_install.push(entry);
+                    }
                 }
             } else {
                 logs.push_str(&format!("\n{} found in cache", raw_module));
@@ -330,27 +788,17 @@ pub async fn handle_powershell_job(
             .collect::<Vec<_>>()
     };
 
-    let installed_modules = fs::read_dir(POWERSHELL_CACHE_DIR)?
-        .filter_map(|x| {
-            x.ok().map(|x| {
-                x.path()
-                    .display()
-                    .to_string()
-                    .split('/')
-                    .last()
-                    .unwrap_or_default()
-                    .to_lowercase()
-            })
-        })
-        .collect::<Vec<String>>();
+    let mut cache_index = PowershellCacheIndex::load();
+    cache_index.seed_from_cache_dir();
 
-    let mut install_string: String = String::new();
+    report_job_phase(db, &job.id, &job.workspace_id, JobPhase::ResolvingDeps).await;
+    let mut modules_to_install: Vec<(String, Option<String>)> = Vec::new();
     let mut logs1 = String::new();
     let mut visited_nodes: HashSet<String> = HashSet::new();
     visited_nodes.insert("main".to_string());
     let mut code_content = handle_powershell_deps(
-        &mut install_string,
-        &installed_modules,
+        &mut modules_to_install,
+        &cache_index,
         &mut visited_nodes,
         job.script_path(),
         &mut logs1,
@@ -360,29 +808,29 @@ pub async fn handle_powershell_job(
     )
     .await?;
 
-    if !install_string.is_empty() {
+    if !modules_to_install.is_empty() {
         logs1.push_str("\n\nInstalling modules...");
         append_logs(&job.id, &job.workspace_id, logs1, db).await;
-        let child = Command::new("pwsh")
-            .args(["-Command", &install_string])
-            .stdout(Stdio::piped())
-            .stderr(Stdio::piped())
-            .spawn()?;
-
-        handle_child(
-            &job.id,
-            db,
-            mem_peak,
-            canceled_by,
-            child,
-            false,
-            worker_name,
-            &job.workspace_id,
-            "powershell install",
-            job.timeout,
-            false,
-        )
-        .await?;
+        report_job_phase(db, &job.id, &job.workspace_id, JobPhase::InstallingModules).await;
+        // install each module independently with bounded retries so a transient PSGallery/network
+        // hiccup on one module doesn't fail the whole job or re-download already-saved modules
+        for (module, version) in &modules_to_install {
+            let install_result = save_powershell_module(
+                module,
+                version.as_deref(),
+                &mut cache_index,
+                mem_peak,
+                canceled_by,
+                job,
+                db,
+                worker_name,
+            )
+            .await;
+            if install_result.is_err() {
+                report_job_phase(db, &job.id, &job.workspace_id, JobPhase::Failed).await;
+            }
+            install_result?;
+        }
     }
 
     let mut logs2 = "".to_string();
@@ -412,20 +860,28 @@ $env:PSModulePath = \"{}:$PSModulePathBackup\"",
         format!("{}\n{}", profile, code_content)
     };
 
+    let structured_output = is_structured_output(content);
+
     write_file(job_dir, "main.ps1", &code_content).await?;
-    write_file(
-        job_dir,
-        "wrapper.sh",
-        &format!("set -o pipefail\nset -e\nmkfifo bp\ncat bp | tail -1 > ./result2.out &\n{} -F ./main.ps1 \"$@\" 2>&1 | tee bp\nwait $!", POWERSHELL_PATH.as_str()),
-    )
-    .await?;
+    let wrapper_content = if structured_output {
+        structured_wrapper(&format!("{} -F ./main.ps1 \"$@\"", POWERSHELL_PATH.as_str()))
+    } else {
+        format!("set -o pipefail\nset -e\nmkfifo bp\ncat bp | tail -1 > ./result2.out &\n{} -F ./main.ps1 \"$@\" 2>&1 | tee bp\nwait $!", POWERSHELL_PATH.as_str())
+    };
+    write_file(job_dir, "wrapper.sh", &wrapper_content).await?;
     let token = client.get_token().await;
     let mut reserved_variables = get_reserved_variables(job, &token, db).await?;
     reserved_variables.insert("RUST_LOG".to_string(), "info".to_string());
 
-    let _ = write_file(job_dir, "result.json", "").await?;
-    let _ = write_file(job_dir, "result.out", "").await?;
-    let _ = write_file(job_dir, "result2.out", "").await?;
+    if structured_output {
+        let _ = write_file(job_dir, "result_stdout.out", "").await?;
+        let _ = write_file(job_dir, "result_stderr.out", "").await?;
+        let _ = write_file(job_dir, "result_exit_code.out", "").await?;
+    } else {
+        let _ = write_file(job_dir, "result.json", "").await?;
+        let _ = write_file(job_dir, "result.out", "").await?;
+        let _ = write_file(job_dir, "result2.out", "").await?;
+    }
 
     let child = if !*DISABLE_NSJAIL {
         let _ = write_file(
@@ -474,7 +930,8 @@ $env:PSModulePath = \"{}:$PSModulePathBackup\"",
             .stderr(Stdio::piped())
             .spawn()?
     };
-    handle_child(
+    report_job_phase(db, &job.id, &job.workspace_id, JobPhase::Running).await;
+    let run_result = handle_child(
         &job.id,
         db,
         mem_peak,
@@ -487,18 +944,31 @@ $env:PSModulePath = \"{}:$PSModulePathBackup\"",
         job.timeout,
         false,
     )
-    .await?;
-
-    let result_out_path2 = format!("{job_dir}/result2.out");
-    if tokio::fs::metadata(&result_out_path2).await.is_ok() {
-        let result = read_file_content(&result_out_path2)
-            .await?
-            .trim()
-            .to_string();
-        return Ok(to_raw_value(&json!(result)));
+    .await;
+    if run_result.is_err() {
+        report_job_phase(db, &job.id, &job.workspace_id, JobPhase::Failed).await;
     }
+    run_result?;
+
+    report_job_phase(db, &job.id, &job.workspace_id, JobPhase::CollectingResult).await;
+
+    let result = if structured_output {
+        // see handle_bash_job: a non-zero exit is surfaced via the result's `exit_code` field, not
+        // as a failed job, so the phase stays Completed.
+        let (result, _exit_code) = collect_structured_output(job_dir).await?;
+        result
+    } else {
+        let result_out_path2 = format!("{job_dir}/result2.out");
+        if tokio::fs::metadata(&result_out_path2).await.is_ok() {
+            let out = read_file_content(&result_out_path2).await?.trim().to_string();
+            to_raw_value(&json!(out))
+        } else {
+            to_raw_value(&json!(
+                "No result.out, result2.out or result.json found"
+            ))
+        }
+    };
 
-    Ok(to_raw_value(&json!(
-        "No result.out, result2.out or result.json found"
-    )))
+    report_job_phase(db, &job.id, &job.workspace_id, JobPhase::Completed).await;
+    Ok(result)
 }